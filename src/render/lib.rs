@@ -25,11 +25,12 @@
 extern crate comm;
 extern crate device;
 
+use std::cell::RefCell;
 use std::fmt::Show;
 use std::vec::MoveItems;
 
 use backend = device::dev;
-use device::shade::{CreateShaderError, ProgramMeta, Vertex, Fragment, ShaderSource};
+use device::shade::{CreateShaderError, ProgramMeta, Vertex, Fragment, Compute, ShaderSource};
 use device::target::{ClearData, TargetColor, TargetDepth, TargetStencil};
 use shade::{BundleInternal, ShaderParam};
 use resource::{Loaded, Pending};
@@ -41,6 +42,8 @@ pub type SamplerHandle = uint;
 pub type ShaderHandle = uint;
 pub type ProgramHandle = uint;
 pub type EnvirHandle = uint;
+pub type QuerySetHandle = uint;
+pub type ReadbackHandle = uint;
 
 pub mod mesh;
 pub mod rast;
@@ -50,11 +53,66 @@ pub mod target;
 
 pub type Token = uint;
 
+/// One plane of an externally allocated image, referenced by a file descriptor. Planar formats
+/// (e.g. YUV from a video decoder) carry several of these.
+pub struct ExternalPlane {
+    /// dmabuf file descriptor backing this plane
+    pub fd: int,
+    /// byte distance between successive rows
+    pub stride: u32,
+    /// byte offset of the plane within the dmabuf
+    pub offset: u32,
+}
+
+/// Describes an externally allocated image to be imported as a texture, as produced by a
+/// hardware video decoder or a Wayland client, for zero-copy interop.
+pub struct ExternalImageDesc {
+    pub width: u16,
+    pub height: u16,
+    pub format: device::tex::Format,
+    /// one entry per plane of the image
+    pub planes: Vec<ExternalPlane>,
+    /// opaque vendor format modifier describing the memory layout
+    pub modifier: u64,
+}
+
+/// How a buffer will be used, which tells the backend what kind of storage to allocate.
+/// `UsageCopy` requests storage that can take part in on-device `copy_*` transfers.
+#[deriving(Clone, PartialEq, Show)]
+pub enum BufferUsage {
+    /// Set once, drawn from many times.
+    UsageStatic,
+    /// Updated from the CPU between draws.
+    UsageDynamic,
+    /// Usable as a source or destination of on-device copies.
+    UsageCopy,
+}
+
+/// The kind of results a `QuerySet` collects.
+#[deriving(Clone, PartialEq, Show)]
+pub enum QueryKind {
+    /// GPU timestamps, written with `write_timestamp`.
+    Timestamp,
+    /// Sample-passed counters, bracketed with `begin_occlusion`/`end_occlusion`.
+    Occlusion,
+}
+
 /// Graphics state
 struct State {
     frame: target::Frame,
 }
 
+/// A presentation surface owning a small ring of color buffers, used for direct scanout instead
+/// of the windowing system's single default framebuffer.
+struct PresentSurface {
+    /// number of color buffers in the ring (double/triple buffered)
+    buffer_count: uint,
+    /// how many submitted buffers are awaiting a swap acknowledgement. The device owns the ring's
+    /// current back buffer and rotates it on each `PresentSurface`; we only track occupancy so we
+    /// know when to throttle.
+    in_flight: uint,
+}
+
 /// An error that can happen when sending commands to the device. Any attempt to use the handles
 /// returned here will fail.
 #[deriving(Clone, Show)]
@@ -137,6 +195,20 @@ impl Dispatcher {
     fn get_texture(&mut self, handle: TextureHandle) -> backend::Texture {
         self.get_any(|res| &res.textures[handle])
     }
+
+    fn get_query_set(&mut self, handle: QuerySetHandle) -> backend::QuerySet {
+        self.get_any(|res| &res.query_sets[handle])
+    }
+}
+
+/// A captured sequence of device commands, recorded once and replayed every frame. Suited to
+/// static passes whose draws are identical each frame, avoiding the per-frame cost of
+/// regenerating the command stream. Produced by `Renderer::record_bundle`.
+pub struct CommandBundle {
+    /// the recorded casts, replayed verbatim on `submit_bundle`
+    commands: Vec<device::CastRequest>,
+    /// the frame state left behind by the recording, restored on replay
+    frame: target::Frame,
 }
 
 /// A renderer. Methods on this get translated into commands for the device.
@@ -147,6 +219,12 @@ pub struct Renderer {
     should_finish: comm::ShouldClose,
     /// the default FBO for drawing
     default_frame_buffer: backend::FrameBuffer,
+    /// when recording a `CommandBundle`, casts are appended here instead of being sent
+    recording: RefCell<Option<Vec<device::CastRequest>>>,
+    /// presentation surfaces created against this renderer
+    surfaces: Vec<PresentSurface>,
+    /// the surface the default frame resolves to, if any
+    present_target: Option<SurfaceHandle>,
     /// current state
     state: State,
 }
@@ -173,15 +251,44 @@ impl Renderer {
             swap_ack: swap_rx,
             should_finish: should_finish,
             default_frame_buffer: 0,
+            recording: RefCell::new(None),
+            surfaces: Vec::new(),
+            present_target: None,
             state: State {
                 frame: target::Frame::new(),
             },
         }
     }
 
-    /// Ask the device to do something for us
+    /// Ask the device to do something for us. While a `CommandBundle` is being recorded the cast
+    /// is appended to the bundle instead of being sent straight to the device.
     fn cast(&self, msg: device::CastRequest) {
-        self.device_tx.send(device::Cast(msg));
+        match *self.recording.borrow_mut() {
+            Some(ref mut commands) => commands.push(msg),
+            None => self.device_tx.send(device::Cast(msg)),
+        }
+    }
+
+    /// Record the draws issued by `f` into a reusable `CommandBundle` rather than sending them to
+    /// the device. Resources are still resolved and `demand`-ed at record time, so replay never
+    /// touches pending handles; the frame state left by the recording is snapshotted too.
+    pub fn record_bundle(&mut self, f: |&mut Renderer|) -> CommandBundle {
+        *self.recording.borrow_mut() = Some(Vec::new());
+        f(self);
+        let commands = self.recording.borrow_mut().take().unwrap();
+        CommandBundle {
+            commands: commands,
+            frame: self.state.frame,
+        }
+    }
+
+    /// Replay a previously recorded `CommandBundle` onto the device channel, amortizing the
+    /// command-generation cost across frames.
+    pub fn submit_bundle(&mut self, bundle: &CommandBundle) {
+        for msg in bundle.commands.iter() {
+            self.device_tx.send(device::Cast(msg.clone()));
+        }
+        self.state.frame = bundle.frame;
     }
 
     /// Whether rendering should stop completely.
@@ -228,7 +335,7 @@ impl Renderer {
             resource::Loaded(ref p) => p,
             resource::Failed(_) => return Err(ErrorProgram),
         };
-        match self.bind_shader_bundle(program, bundle) {
+        match self.bind_shader_bundle(program, bundle, false) {
             Ok(_) => (),
             Err(e) => return Err(ErrorBundle(e)),
         }
@@ -251,11 +358,88 @@ impl Renderer {
         Ok(())
     }
 
-    /// Finish rendering a frame. Waits for a frame to be finished drawing, as specified by the
-    /// queue size passed to `gfx::start`.
-    pub fn end_frame(&self) {
-        self.device_tx.send(device::SwapBuffers);
-        self.swap_ack.recv();  //wait for acknowlegement
+    /// Dispatch a compute `bundle` over a grid of `groups` work groups. The bundle is bound with
+    /// the same path as `draw` (`bind_shader_bundle`), so uniforms and textures resolve
+    /// identically; block buffers are bound as storage buffers, which occupy their own slots on
+    /// the device side. Remember to insert
+    /// a `memory_barrier` before reading the results from a subsequent `draw`.
+    pub fn dispatch<'a, L, T: shade::ShaderParam<L>>(&'a mut self,
+            bundle: &shade::ShaderBundle<L, T>, groups: [u32, ..3]) -> Result<(), DrawError<'a>> {
+        self.prebind_bundle(bundle);
+        self.dispatcher.demand(|res| !res.programs[bundle.get_program()].is_pending());
+        let program = match self.dispatcher.resource.programs[bundle.get_program()] {
+            resource::Pending => fail!("Program is not loaded yet"),
+            resource::Loaded(ref p) => p,
+            resource::Failed(_) => return Err(ErrorProgram),
+        };
+        match self.bind_shader_bundle(program, bundle, true) {
+            Ok(_) => (),
+            Err(e) => return Err(ErrorBundle(e)),
+        }
+        self.cast(device::Dispatch(groups[0], groups[1], groups[2]));
+        Ok(())
+    }
+
+    /// Insert a memory barrier so writes issued by a preceding `dispatch` become visible to
+    /// later draws or dispatches that read the same buffers or textures.
+    pub fn memory_barrier(&self) {
+        self.cast(device::MemoryBarrier);
+    }
+
+    /// Finish rendering a frame. When a presentation surface is the current target, a page-flip is
+    /// queued on it and the device rotates to the next back buffer; we only block for a swap
+    /// acknowledgement once every buffer is in flight. The ack contract is one `swap_ack` per
+    /// *freed* buffer, so we first drain the acks that already arrived (decrementing the in-flight
+    /// count for each) and then block for one more only if the whole ring is still occupied.
+    /// Otherwise this swaps the default framebuffer and waits, as specified by the queue size
+    /// passed to `gfx::start`.
+    pub fn end_frame(&mut self) {
+        match self.present_target {
+            Some(s) => {
+                let count = self.surfaces[s].buffer_count;
+                self.device_tx.send(device::PresentSurface(s));
+                self.surfaces.get_mut(s).in_flight += 1;
+                // drain buffers the device has already released
+                loop {
+                    match self.swap_ack.try_recv() {
+                        Ok(_) => self.surfaces.get_mut(s).in_flight -= 1,
+                        Err(_) => break,
+                    }
+                }
+                if self.surfaces[s].in_flight >= count {
+                    self.swap_ack.recv();  //all buffers busy, wait for one to free
+                    self.surfaces.get_mut(s).in_flight -= 1;
+                }
+            },
+            None => {
+                self.device_tx.send(device::SwapBuffers);
+                self.swap_ack.recv();  //wait for acknowlegement
+            },
+        }
+    }
+
+    /// Create a presentation surface owning a ring of `buffer_count` color buffers of the given
+    /// format and size, for direct scanout. Make it the render target with `set_present_target`.
+    pub fn create_present_surface(&mut self, format: device::tex::Format, width: u16, height: u16,
+            buffer_count: uint) -> SurfaceHandle {
+        assert!(buffer_count >= 1, "a present surface needs at least one color buffer");
+        let handle = self.surfaces.len();
+        // Fire-and-forget: surfaces are tracked renderer-side rather than in `resource::Cache`, so
+        // creation expects no reply. The ordered channel guarantees the surface exists before any
+        // later `BindPresentBuffer`/`PresentSurface` for the same handle is processed.
+        self.device_tx.send(device::Cast(
+            device::CreatePresentSurface(handle, format, width, height, buffer_count)));
+        self.surfaces.push(PresentSurface {
+            buffer_count: buffer_count,
+            in_flight: 0,
+        });
+        handle
+    }
+
+    /// Route the default frame to `surface`'s current back buffer instead of the windowing
+    /// system's default framebuffer.
+    pub fn set_present_target(&mut self, surface: SurfaceHandle) {
+        self.present_target = Some(surface);
     }
 
     /// Create a new program from the given vertex and fragment shaders.
@@ -274,12 +458,28 @@ impl Renderer {
         token
     }
 
-    /// Create a new buffer on the device, which can be used to store vertex or uniform data.
-    pub fn create_buffer<T: Send>(&mut self, data: Option<Vec<T>>) -> BufferHandle {
+    /// Create a new compute program from the given compute shader. Unlike `create_program` this
+    /// links a single `Compute` stage shader into the program.
+    pub fn create_compute_program(&mut self, cs_src: ShaderSource) -> ProgramHandle {
+        let ds = &mut self.dispatcher;
+        let id = ds.resource.shaders.len();
+        ds.resource.shaders.push(Pending);
+        self.device_tx.send(device::Call(id, device::CreateShader(Compute, cs_src)));
+        let h_cs = ds.get_shader(id);
+        let token = ds.resource.programs.len();
+        self.device_tx.send(device::Call(token, device::CreateProgram(vec![h_cs])));
+        ds.resource.programs.push(Pending);
+        token
+    }
+
+    /// Create a new buffer on the device, which can be used to store vertex or uniform data. The
+    /// `usage` hint tells the backend what kind of storage to allocate; pass `UsageCopy` for a
+    /// buffer that will be a source or destination of on-device `copy_*` transfers.
+    pub fn create_buffer<T: Send>(&mut self, data: Option<Vec<T>>, usage: BufferUsage) -> BufferHandle {
         let bufs = &mut self.dispatcher.resource.buffers;
         let token = bufs.len();
         let blob = data.map(|v| (box v) as Box<device::Blob + Send>);
-        self.device_tx.send(device::Call(token, device::CreateBuffer(blob)));
+        self.device_tx.send(device::Call(token, device::CreateBuffer(blob, usage)));
         bufs.push(Pending);
         token
     }
@@ -287,7 +487,7 @@ impl Renderer {
     pub fn create_mesh<T: mesh::VertexFormat + Send>(&mut self, data: Vec<T>) -> mesh::Mesh {
         let nv = data.len();
         debug_assert!(nv < 0x10000);
-        let buf = self.create_buffer(Some(data));
+        let buf = self.create_buffer(Some(data), UsageStatic);
         mesh::Mesh::from::<T>(buf, nv as mesh::VertexCount)
     }
 
@@ -299,6 +499,18 @@ impl Renderer {
         token
     }
 
+    /// Import an externally allocated image (e.g. a dmabuf from a video decoder or compositor) as
+    /// a texture, instead of allocating and uploading through `create_texture`. The imported
+    /// storage flows through `resource::Cache` and the `bind_shader_bundle` path like any other
+    /// texture, so it can be sampled with no copy.
+    pub fn import_texture(&mut self, desc: ExternalImageDesc) -> TextureHandle {
+        let texs = &mut self.dispatcher.resource.textures;
+        let token = texs.len();
+        self.device_tx.send(device::Call(token, device::ImportTexture(desc)));
+        texs.push(Pending);
+        token
+    }
+
     pub fn create_sampler(&mut self, info: device::tex::SamplerInfo) -> SamplerHandle {
         let sams = &mut self.dispatcher.resource.samplers;
         let token = sams.len();
@@ -307,6 +519,44 @@ impl Renderer {
         token
     }
 
+    /// Create a query set holding `count` slots of the given `kind`, used to measure GPU timing or
+    /// visibility. Results are later copied into a buffer with `resolve_query`.
+    pub fn create_query_set(&mut self, kind: QueryKind, count: uint) -> QuerySetHandle {
+        let sets = &mut self.dispatcher.resource.query_sets;
+        let token = sets.len();
+        self.device_tx.send(device::Call(token, device::CreateQuerySet(kind, count)));
+        sets.push(Pending);
+        token
+    }
+
+    /// Write the current GPU timestamp into slot `index` of a `Timestamp` query set.
+    pub fn write_timestamp(&mut self, set: QuerySetHandle, index: uint) {
+        let qs = self.dispatcher.get_query_set(set);
+        self.cast(device::WriteTimestamp(qs, index));
+    }
+
+    /// Begin counting samples that pass the depth/stencil tests into slot `index` of an
+    /// `Occlusion` query set. Wraps the draws whose visibility is being measured.
+    pub fn begin_occlusion(&mut self, set: QuerySetHandle, index: uint) {
+        let qs = self.dispatcher.get_query_set(set);
+        self.cast(device::BeginOcclusion(qs, index));
+    }
+
+    /// Stop the occlusion count started by `begin_occlusion` for slot `index`.
+    pub fn end_occlusion(&mut self, set: QuerySetHandle, index: uint) {
+        let qs = self.dispatcher.get_query_set(set);
+        self.cast(device::EndOcclusion(qs, index));
+    }
+
+    /// Resolve the `range` of slots of a query set into `dest`, so the results can be read back
+    /// from the GPU buffer later.
+    pub fn resolve_query(&mut self, set: QuerySetHandle, range: (uint, uint), dest: BufferHandle) {
+        let qs = self.dispatcher.get_query_set(set);
+        let buf = self.dispatcher.get_buffer(dest);
+        let (start, end) = range;
+        self.cast(device::ResolveQuery(qs, start, end, buf));
+    }
+
     pub fn bundle_program<'a, L, T: shade::ShaderParam<L>>(&'a mut self, prog: ProgramHandle, data: T)
             -> Result<shade::ShaderBundle<L, T>, shade::ParameterLinkError<'a>> {
         self.dispatcher.demand(|res| !res.programs[prog].is_pending());
@@ -343,6 +593,60 @@ impl Renderer {
         self.cast(device::UpdateTexture(tex, info, (box data) as Box<device::Blob + Send>));
     }
 
+    /// Copy `size` bytes from one buffer to another on the device, without a CPU round-trip. Both
+    /// buffers should have been created with `UsageCopy`.
+    pub fn copy_buffer_to_buffer(&mut self, src: BufferHandle, src_offset: uint,
+                                 dst: BufferHandle, dst_offset: uint, size: uint) {
+        let from = self.dispatcher.get_buffer(src);
+        let to = self.dispatcher.get_buffer(dst);
+        self.cast(device::CopyBuffer(from, src_offset, to, dst_offset, size));
+    }
+
+    /// Copy pixel data from a buffer into the region of `dst_tex` described by `info`.
+    pub fn copy_buffer_to_texture(&mut self, src: BufferHandle, dst_tex: TextureHandle,
+                                  info: device::tex::ImageInfo) {
+        let from = self.dispatcher.get_buffer(src);
+        let to = self.dispatcher.get_texture(dst_tex);
+        self.cast(device::CopyBufferToTexture(from, to, info));
+    }
+
+    /// Copy the region of `src_tex` described by `info` into a buffer, e.g. to feed a readback.
+    pub fn copy_texture_to_buffer(&mut self, src_tex: TextureHandle,
+                                  info: device::tex::ImageInfo, dst: BufferHandle) {
+        let from = self.dispatcher.get_texture(src_tex);
+        let to = self.dispatcher.get_buffer(dst);
+        self.cast(device::CopyTextureToBuffer(from, info, to));
+    }
+
+    /// Start an asynchronous read back of a buffer's contents. A `ReadBuffer` call is issued to the
+    /// device, which replies over the usual `Reply` channel; `resource::Cache::process` stores the
+    /// returned bytes into the pending readback `Future`. This waits only until the source buffer
+    /// itself is loaded, then returns a handle without waiting for the data; the caller later retrieves the data with `get_readback`, or checks `is_readback_pending`
+    /// without blocking.
+    pub fn read_buffer(&mut self, handle: BufferHandle) -> ReadbackHandle {
+        let buf = self.dispatcher.get_buffer(handle);
+        let token = self.dispatcher.resource.readbacks.len();
+        self.device_tx.send(device::Call(token, device::ReadBuffer(buf)));
+        self.dispatcher.resource.readbacks.push(Pending);
+        token
+    }
+
+    /// Whether the readback started by `read_buffer` has not yet resolved. Does not block.
+    pub fn is_readback_pending(&self, handle: ReadbackHandle) -> bool {
+        self.dispatcher.resource.readbacks[handle].is_pending()
+    }
+
+    /// Retrieve the data of a readback started by `read_buffer`, demanding device replies through
+    /// the `Dispatcher` until it resolves.
+    pub fn get_readback(&mut self, handle: ReadbackHandle) -> Result<Vec<u8>, DeviceError> {
+        self.dispatcher.demand(|res| !res.readbacks[handle].is_pending());
+        match self.dispatcher.resource.readbacks[handle] {
+            resource::Loaded(ref data) => Ok(data.clone()),
+            resource::Failed(ref e) => Err(e.clone()),
+            resource::Pending => fail!("Readback is not ready yet"),
+        }
+    }
+
     /// Make sure all the mesh buffers are successfully created/loaded
     fn prebind_mesh(&mut self, mesh: &mesh::Mesh, slice: &mesh::Slice) {
         for at in mesh.attributes.iter() {
@@ -378,20 +682,29 @@ impl Renderer {
 
     fn bind_frame(&mut self, frame: &target::Frame) {
         if frame.is_default() {
-            // binding the default FBO, not touching our common one
-            self.cast(device::BindFrameBuffer(self.default_frame_buffer));
+            match self.present_target {
+                // Bind the surface's current back buffer, resolved device-side. No ring index is
+                // baked in, so this stays correct when captured in a `CommandBundle` and replayed
+                // across rotating frames.
+                Some(s) => self.cast(device::BindPresentBuffer(s)),
+                // binding the default FBO, not touching our common one
+                None => self.cast(device::BindFrameBuffer(self.default_frame_buffer)),
+            }
         } else {
+            // While recording a bundle the captured commands must be self-contained, so bind every
+            // target in full rather than emitting deltas against the live frame state.
+            let force = self.recording.borrow().is_some();
             let fbo = self.dispatcher.get_common_frame_buffer();
             self.cast(device::BindFrameBuffer(fbo));
             for (i, (cur, new)) in self.state.frame.colors.iter().zip(frame.colors.iter()).enumerate() {
-                if *cur != *new {
+                if force || *cur != *new {
                     self.cast(device::BindTarget(TargetColor(i as u8), *new));
                 }
             }
-            if self.state.frame.depth != frame.depth {
+            if force || self.state.frame.depth != frame.depth {
                 self.cast(device::BindTarget(TargetDepth, frame.depth));
             }
-            if self.state.frame.stencil != frame.stencil {
+            if force || self.state.frame.stencil != frame.stencil {
                 self.cast(device::BindTarget(TargetStencil, frame.stencil));
             }
             self.state.frame = *frame;
@@ -399,9 +712,10 @@ impl Renderer {
     }
 
     fn bind_shader_bundle<L, T: shade::ShaderParam<L>>(&self, meta: &ProgramMeta,
-            bundle: &shade::ShaderBundle<L, T>) -> Result<(), BundleError> {
+            bundle: &shade::ShaderBundle<L, T>, compute: bool) -> Result<(), BundleError> {
         self.cast(device::BindProgram(meta.name));
         let mut block_slot   = 0u as device::UniformBufferSlot;
+        let mut storage_slot = 0u as device::StorageBufferSlot;
         let mut texture_slot = 0u as device::TextureSlot;
         let mut block_fail   = None::<shade::VarBlock>;
         let mut texture_fail = None::<shade::VarTexture>;
@@ -409,6 +723,15 @@ impl Renderer {
             self.cast(device::BindUniform(meta.uniforms[uv as uint].location, value));
         }, |bv, handle| {
             match self.dispatcher.resource.buffers[handle] {
+                // compute programs address their block buffers as writable storage buffers,
+                // which live in their own slot space alongside the uniform-buffer slots.
+                Loaded(block) if compute => {
+                    self.cast(device::BindStorageBuffer(meta.name,
+                        storage_slot as device::StorageBufferSlot,
+                        bv as device::UniformBlockIndex,
+                        block));
+                    storage_slot += 1;
+                },
                 Loaded(block) => {
                     self.cast(device::BindUniformBlock(meta.name,
                         block_slot as device::UniformBufferSlot,